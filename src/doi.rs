@@ -0,0 +1,19 @@
+//! Extraction of the canonical DOI from a `doi` field's text.
+//!
+//! DOIs are sometimes written decorated, e.g. as a full
+//! `https://doi.org/10.1038/x` URL instead of the bare `10.1038/x`.
+//! Comparing field text verbatim would treat those as different DOIs, so
+//! duplicate detection and `--merge` instead extract the `10.xxxx/...`
+//! identifier itself.
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+lazy_static! {
+    static ref DOI: Regex = Regex::new(r"10\.[\)\(\.\w/\-:]+").unwrap();
+}
+
+/// Extracts the canonical `10.xxxx/...` DOI from a field's text, if any.
+pub fn extract(text: &str) -> Option<&str> {
+    DOI.find(text).map(|m| m.as_str())
+}
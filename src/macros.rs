@@ -0,0 +1,46 @@
+//! Expansion of `@string` macros.
+//!
+//! A `.bib` file can define macros with `@string{jnl = {Journal Name}}`
+//! and then reference them unquoted in a field, optionally concatenated
+//! with literal text via `#`, e.g. `journal = jnl # { Letters}`. This
+//! module resolves those references against the macros defined earlier
+//! in the same file.
+
+use std::collections::HashMap;
+
+use crate::parser::{BibEntry, EntryKind, FieldValue, ValuePart};
+
+/// Builds a name -> value map from the `@string` entries in `preserved`,
+/// in file order, so a later macro's definition may reference an earlier
+/// one.
+pub fn collect(preserved: &[BibEntry]) -> HashMap<String, FieldValue> {
+    let mut macros = HashMap::new();
+    for entry in preserved {
+        if entry.kind != EntryKind::StringDef {
+            continue;
+        }
+        let (Some(name), Some(value)) = (&entry.key, entry.field("value")) else {
+            continue;
+        };
+        let expanded = expand(value, &macros);
+        macros.insert(name.clone(), expanded);
+    }
+    macros
+}
+
+/// Replaces every [`ValuePart::Bare`] part that names a known macro with
+/// that macro's (already-expanded) parts. Parts that don't match a macro
+/// are left untouched, including numbers such as `year = 2019`.
+pub fn expand(value: &FieldValue, macros: &HashMap<String, FieldValue>) -> FieldValue {
+    let mut parts = Vec::with_capacity(value.parts.len());
+    for part in &value.parts {
+        match part {
+            ValuePart::Bare(name) => match macros.get(&name.to_lowercase()) {
+                Some(resolved) => parts.extend(resolved.parts.iter().cloned()),
+                None => parts.push(part.clone()),
+            },
+            other => parts.push(other.clone()),
+        }
+    }
+    FieldValue { parts }
+}
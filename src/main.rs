@@ -1,19 +1,26 @@
 use std::{
-    collections::BTreeSet, 
-    fs::File, 
-    io::{stdout, BufRead, BufReader, BufWriter, Write}, 
-    path::PathBuf, 
-    process::exit
+    collections::HashMap,
+    fs::read_to_string,
+    io::{stdout, BufWriter, Write},
+    path::PathBuf,
+    process::exit,
 };
 use clap::Parser;
 
+mod doi;
+mod macros;
+mod merge;
+mod parser;
 mod sort_by_author;
+mod sort_by_field;
+
+use parser::{BibEntry, EntryKind};
 
 /// Created by Yannick Feld
-/// 
+///
 /// The program is intended to sort bibfiles by the key.
 /// For example
-/// 
+///
 /// @article{boers2019,
 ///     author = {N. Boers AND B. Goswami AND A. Rheinwalt AND B. Bookhagen AND B. Hoskins AND J. Kurths},
 ///     title = {Complex networks reveal global pattern of extreme-rainfall teleconnections},
@@ -23,20 +30,24 @@ mod sort_by_author;
 ///     pages = {373-377},
 ///     doi = {10.1038/s41586-018-0872-x}
 /// }
-/// 
-/// 
+///
+///
 /// Here the key is boers2019
-/// 
-/// Note: If you want to overwrite the bibfile: Do NOT pipe into it. 
-/// Commands like bib_sort literature.bib > literature.bib will DELETE the literature.bib file 
+///
+/// Note: If you want to overwrite the bibfile: Do NOT pipe into it.
+/// Commands like bib_sort literature.bib > literature.bib will DELETE the literature.bib file
 /// before the program reads it, which means it is essentially just creating an empty file.
-/// 
+///
 /// INSTEAD: You can safely use bib_sort literature.bib -o literature.bib as this will first parse
 /// the bibfile and then overwrite it with the sorted file only if no errors were detected.
 #[derive(Parser)]
 pub struct Opts{
-    /// Path to the current bib file
-    bib_path: PathBuf,
+    /// Path to the bib file(s) to sort. If more than one is given, all
+    /// of their entries are parsed and concatenated before sorting and
+    /// duplicate detection, so several bibliographies can be folded into
+    /// one sorted output with a single command.
+    #[arg(required = true, num_args = 1..)]
+    bib_path: Vec<PathBuf>,
 
     #[arg(long, short)]
     /// Make sorting case sensitive
@@ -72,249 +83,210 @@ pub struct Opts{
     #[arg(long, alias="aed")]
     allow_empty_doi: bool,
 
-    /// Parses the "author = " part, truncates it such that it only contains 
-    /// the first author and uses that to sort. This sorting depends on the ordering of 
+    /// Parses the "author = " part, truncates it such that it only contains
+    /// the first author and uses that to sort. This sorting depends on the ordering of
     /// first and last name in the bib entry
     /// [alias: --sba]
-    #[arg(long, alias="sbfaf", conflicts_with = "sort_by_first_author_first_name")]
+    #[arg(
+        long,
+        alias="sbfaf",
+        conflicts_with_all = ["sort_by_first_author_first_name", "sort_by"]
+    )]
     sort_by_first_author_field: bool,
 
-    /// Parses the "author = " part, truncates it such that it only contains 
-    /// the first author. Then it tries to put the first name of the 
-    /// author in front of the last name, if the order was reversed.
-    /// This is then used for sorting.
+    /// Parses the "author = " part, truncates it such that it only contains
+    /// the first author, and sorts by its "Last von First Jr" key - the
+    /// canonical BibTeX name order - so the surname is what determines
+    /// the sort position regardless of how the name was written in the
+    /// field.
     /// [alias: --sbfafn]
-    #[arg(long, alias="sbfafn")]
-    sort_by_first_author_first_name: bool
-}
-
-pub struct LineIterHelper<I>{
-    pub line_iter: I,
-    pub leftover: Option<String>
-}
-
-impl<I> Iterator for LineIterHelper<I>
-where I: Iterator<Item=String>
-{
-    type Item = String;
-    fn next(&mut self) -> Option<Self::Item> {
-        self.leftover
-            .take()
-            .or_else(|| self.line_iter.next())
-    }
-}
-
-impl<I> LineIterHelper<I>
-    where I: Iterator<Item = String>
-{
-    pub fn new(line_iter: I) -> Self
-    {
-        Self {
-            leftover: None,
-            line_iter
-        }
-    }
+    #[arg(long, alias="sbfafn", conflicts_with = "sort_by")]
+    sort_by_first_author_first_name: bool,
+
+    /// Sorts by the given field (e.g. "year", "journal", "title") instead
+    /// of by key or first author. Numeric-looking fields are compared
+    /// numerically rather than lexically. Entries missing the field sort
+    /// first; ties are always broken by key.
+    #[arg(long)]
+    sort_by: Option<String>,
+
+    /// Reverses the order of the --sort-by comparison. Has no effect on
+    /// the other sorting modes.
+    #[arg(long)]
+    reverse: bool,
+
+    /// By default, `@string` macros referenced in a field (e.g.
+    /// `journal = jnl # { Letters}`) are expanded using the `@string`
+    /// definitions found in the file. This flag keeps them unexpanded.
+    #[arg(long)]
+    no_string_expansion: bool,
+
+    /// Instead of aborting when two entries share a key (or, unless
+    /// --add is used, a DOI), combine them into one entry: fields are
+    /// unioned, the longer/non-empty value wins when both define the
+    /// same field, and genuine conflicts are reported on stderr. Useful
+    /// for consolidating bibliographies pulled from multiple managers.
+    #[arg(long)]
+    merge: bool
 }
 
-
 fn main() {
 
     let opts = Opts::parse();
 
-    let reader = File::open(opts.bib_path)
-        .expect("Cannot open bibfile");
-    let buf_reader = BufReader::new(reader);
+    // Each entry is tagged with the file it came from so cross-file
+    // duplicate keys/DOIs can be reported with their originating
+    // filename; the tag is dropped again once duplicate detection (or
+    // --merge) is done with it.
+    let mut tagged_entries: Vec<(&PathBuf, BibEntry)> = Vec::new();
+    for path in &opts.bib_path {
+        let content = read_to_string(path)
+            .unwrap_or_else(|e| panic!("Cannot open bibfile '{}': {e}", path.display()));
+        let parsed = parser::parse(&content)
+            .unwrap_or_else(|e| panic!("Error while parsing '{}': {e}", path.display()));
+        tagged_entries.extend(parsed.into_iter().map(|entry| (path, entry)));
+    }
 
-    let lines = buf_reader
-        .lines()
-        .map(|entry| entry.expect("Error reading line - your bibfile needs to be encoded with UTF8"));
-    let mut line_iter_helper = LineIterHelper::new(lines);
+    let case_fn = get_string_case_fn(opts.case_sensitive);
 
-    let mut entries = Vec::new();
+    // @string/@preamble/@comment blocks have no key and are not citable,
+    // so they are kept out of the sorted, duplicate-checked entry list
+    // and re-emitted verbatim, in their original order, before it.
+    let (preserved_tagged, mut entries_tagged): (Vec<_>, Vec<_>) = tagged_entries
+        .into_iter()
+        .partition(|(_, entry)| !matches!(entry.kind, EntryKind::Entry(_)));
+    let preserved: Vec<BibEntry> = preserved_tagged.into_iter().map(|(_, entry)| entry).collect();
 
-    let case_fn = if opts.case_sensitive {
-        str::to_owned
-    } else {
-        str::to_lowercase
-    };
-
-    // regex for where bibentries start
-    let entry_start = r"@.*\{";
-    let re = regex::Regex::new(entry_start).unwrap();
-    let id_regex = regex::Regex::new(r"[^,\s]+")
-        .unwrap();
-
-    while let Some(line) = line_iter_helper.next() {
-        let no_leading_whitespace = line.trim_start();
-        if no_leading_whitespace.is_empty(){
-            continue;
-        }
-        if !no_leading_whitespace.starts_with('@'){
-            panic!("Missmatched brackets? Encountered line outside bib items that does not start with @, i.e., that does not start a new bib item. Line was {line}");
+    if !opts.allow_empty_keys {
+        if let Some((path, entry)) = entries_tagged.iter().find(|(_, entry)| entry.key.is_none()) {
+            panic!(
+                "Cannot find key in entry of kind {:?} in '{}' - use --allow-empty-keys if this is intentional",
+                entry.kind,
+                path.display()
+            );
         }
+    }
 
-        let id = match re.find(no_leading_whitespace)
-        {
-            Some(m) => {
-                let entry_line = &no_leading_whitespace[m.end()..];
-                match id_regex.find(entry_line) {
-                    None => {
-                        if opts.allow_empty_keys{
-                            "".to_owned()
-                        } else {
-                            panic!("Cannot find key in line: {line}")
-                        }
-                    },
-                    Some(id_match) => {
-                        case_fn(id_match.as_str())
-                    }
-                }
-            },
-            None => {
-                panic!("Line without whitespaces starts with @ - but cannot parse - Missing {{?");
+    if !opts.no_string_expansion {
+        let string_macros = macros::collect(&preserved);
+        for (_, entry) in entries_tagged.iter_mut() {
+            for (_, value) in entry.fields.iter_mut() {
+                *value = macros::expand(value, &string_macros);
             }
-        };
-
-        let mut bracket_counter = BracketCounter::default();
-        let mut content = bracket_counter.count_brackets_return_content(
-            no_leading_whitespace,
-            &mut line_iter_helper
-        );
-        
-
-        while !bracket_counter.equal_brackets() {
-            let next_line = line_iter_helper.next()
-                .expect("Unexpected end of file - did you forget to close a bracket?");
-            
-            content.push('\n');
-            content.push_str(
-                &bracket_counter.count_brackets_return_content(
-                    &next_line,
-                    &mut line_iter_helper
-                )
-            );
         }
-        let bib_entry = BibEntry{
-            id,
-            content
-        };
-
-        entries.push(bib_entry);
     }
-    // Dropping line_iter_helper such that 
-    // the file handle of the reader is dropped as well,
-    // so there is no issue writing to the same file 
-    // if the user wants to
-    drop(line_iter_helper);
-
-
-    entries.sort_by_cached_key(|entry| entry.id.clone());
-    if !opts.no_duplicate_detection{
-        let mut detected_duplicates = false;
-        entries.windows(2)
-            .for_each(
-                |slice|
-                {
-                    // Either there are no empty keys, or they were explicitly allowed 
-                    // and in that case they are exempt from duplication detection
-                    if !slice[0].id.is_empty() && slice[0].id == slice[1].id {
-                        detected_duplicates = true;
-                        eprintln!("Duplicate key: {}", slice[0].id);
-                    }
-                }
-            );
 
-        if !opts.allow_doi_duplicates {
-            let mut doi_set = BTreeSet::new();
+    entries_tagged.sort_by_cached_key(|(_, entry)| case_fn(entry.key.clone().unwrap_or_default()));
 
-            let doi_position_regex = regex::Regex::new(r"(?i)\bdoi\s*=\s*")
-                .unwrap();
-            let doi_regex = regex::Regex::new(r"10\.[\)\(\.\w/\-:]+")
-                .unwrap();
-
-            for BibEntry { content, id } in entries.iter()
-            {
-                // ignore items with empty id
-                if id.is_empty() {
-                    continue;
-                }
+    let mut entries: Vec<BibEntry>;
 
-                // check if it contains something like "doi = " (case insensitive)
-                if let Some(doi_pos_match) = doi_position_regex.find(content)
-                {
-                    let mut str_containing_doi_next = &content[doi_pos_match.end()..];
-                    // doi comes before "," if there is any ","
-                    // - If there is no Doi given in the doi field, this makes sure the regex does not try 
-                    // to find the Doi in other parts of the bibitem
-                    if let Some((doi_part, ..)) = str_containing_doi_next.split_once(',')
-                    {
-                        str_containing_doi_next = doi_part;
-                    }
-
-                    match doi_regex.find(str_containing_doi_next)
+    if !opts.no_duplicate_detection && opts.merge {
+        let plain_entries: Vec<BibEntry> = entries_tagged.into_iter().map(|(_, entry)| entry).collect();
+        entries = merge::merge_duplicates(plain_entries, case_fn.clone(), opts.allow_doi_duplicates);
+    } else {
+        if !opts.no_duplicate_detection {
+            let mut detected_duplicates = false;
+            entries_tagged.windows(2)
+                .for_each(
+                    |slice|
                     {
-                        None => {
-                            if opts.allow_empty_doi {
-                                continue;
-                            }
-                            panic!(
-                                "Error - cannot parse DOI in item with key {id}, even though it contains\n'{}'\nFull content of item is\n{content}", 
-                                doi_pos_match.as_str()
-                            );
-                        },
-                        Some(doi) => {
-                            let item_was_not_previously_inserted = doi_set.insert(doi.as_str());
-                            if !item_was_not_previously_inserted {
+                        let ((path_a, a), (path_b, b)) = (&slice[0], &slice[1]);
+                        // Either there are no empty keys, or they were explicitly allowed
+                        // and in that case they are exempt from duplication detection
+                        if let (Some(key_a), Some(key_b)) = (&a.key, &b.key) {
+                            if !key_a.is_empty() && case_fn(key_a.clone()) == case_fn(key_b.clone()) {
                                 detected_duplicates = true;
-                                eprintln!("Duplicate DOI: {}", doi.as_str());
+                                eprintln!(
+                                    "Duplicate key: {key_a} (in '{}' and '{}')",
+                                    path_a.display(),
+                                    path_b.display()
+                                );
+                            }
+                        }
+                    }
+                );
+
+            if !opts.allow_doi_duplicates {
+                let mut doi_map: HashMap<String, &PathBuf> = HashMap::new();
+
+                for (path, entry) in entries_tagged.iter() {
+                    let Some(key) = &entry.key else { continue };
+
+                    match entry.field("doi") {
+                        None => continue,
+                        Some(doi_value) => {
+                            let doi_text = doi_value.as_text();
+                            let Some(doi) = doi::extract(doi_text.trim()) else {
+                                if opts.allow_empty_doi {
+                                    continue;
+                                }
+                                panic!(
+                                    "Error - cannot parse DOI in item with key {key} in '{}', even though it has a doi field with content '{}'",
+                                    path.display(),
+                                    doi_text.trim()
+                                );
+                            };
+                            match doi_map.insert(doi.to_owned(), path) {
+                                None => {},
+                                Some(first_path) => {
+                                    detected_duplicates = true;
+                                    eprintln!(
+                                        "Duplicate DOI: {doi} (in '{}' and '{}')",
+                                        first_path.display(),
+                                        path.display()
+                                    );
+                                }
                             }
                         }
                     }
-
                 }
             }
+
+            if detected_duplicates {
+                panic!(
+                    "The program detected that your file contains either at least one duplicate key or at least one duplicate doi (see previous error messages)!\n\
+                     Please have a look at your file and fix this error before trying again \
+                     or have a look at the options starting with --allow\n\
+                     You can find all options by using --help\n\
+                     Aborted writing anything."
+                )
+            }
         }
-        
-        if detected_duplicates {
-            panic!(
-                "The program detected that your file contains either at least one duplicate key or at least one duplicate doi (see previous error messages)!\n\
-                 Please have a look at your file and fix this error before trying again \
-                 or have a look at the options starting with --allow\n\
-                 You can find all options by using --help\n\
-                 Aborted writing anything."
-            )
-        }
+
+        entries = entries_tagged.into_iter().map(|(_, entry)| entry).collect();
     }
 
     if opts.sort_by_first_author_field{
-        let case_fn = get_string_case_fn(opts.case_sensitive);
-
         sort_by_author::sort_by_first_author_field(
             &mut entries,
-            case_fn
+            case_fn.clone()
         );
     }
 
     if opts.sort_by_first_author_first_name {
-        let case_fn = get_string_case_fn(opts.case_sensitive);
-
         sort_by_author::sort_by_first_author_first_name(
-            &mut entries, 
-            case_fn
+            &mut entries,
+            case_fn.clone()
         );
     }
-    
+
+    if let Some(field) = &opts.sort_by {
+        sort_by_field::sort_by_field(&mut entries, &field.to_lowercase(), case_fn, opts.reverse);
+    }
+
+    let all_entries: Vec<BibEntry> = preserved.into_iter().chain(entries).collect();
+
     match opts.out{
         None => {
             let out = stdout();
-            write_entries(entries, out);
+            write_entries(all_entries, out);
         },
         Some(out_path) => {
-            let out_file = File::create(out_path)
+            let out_file = std::fs::File::create(out_path)
                 .expect("Unable to create file");
             let out = BufWriter::new(out_file);
-            write_entries(entries, out);
-        } 
+            write_entries(all_entries, out);
+        }
     }
 
 
@@ -322,7 +294,7 @@ fn main() {
 
 pub fn write_entries<W: Write>(entries: Vec<BibEntry>, mut out: W){
     for entry in entries{
-        let io_result = writeln!(out, "{}\n", entry.content);
+        let io_result = writeln!(out, "{}\n", parser::format_entry(&entry));
         if let Err(e) = io_result{
             // ignore broken pipes
             if e.kind() == std::io::ErrorKind::BrokenPipe {
@@ -334,61 +306,7 @@ pub fn write_entries<W: Write>(entries: Vec<BibEntry>, mut out: W){
     }
 }
 
-#[derive(Debug)]
-pub struct BibEntry{
-    pub id: String,
-    pub content: String
-}
-
-
-#[derive(Clone, Copy, Debug, Default)]
-pub struct BracketCounter{
-    open: u32,
-    close: u32
-}
-
-impl BracketCounter{
-    pub fn equal_brackets(&self) -> bool 
-    {
-        self.open == self.close
-    }
-
-    fn count_brackets_return_content<I>(
-        &mut self, 
-        s: &str, 
-        line_iter_helper: &mut LineIterHelper<I>
-    ) -> String
-    {
-        let mut char_iter = s.chars();
-        let mut content = String::new();
-        for c in &mut char_iter{
-            content.push(c);
-            match c {
-                '{' => {
-                    self.open += 1;
-                },
-                '}' => {
-                    self.close += 1;
-                    if self.equal_brackets() {
-                        let leftover: String = char_iter.collect();
-                        if !leftover.is_empty(){
-                            line_iter_helper.leftover = Some(leftover);
-                        }
-                        return content;
-                    } else if self.close > self.open {
-                        panic!("Bracket was closed before it was opened! Mismatched bracket error in: {s}");
-                    }
-
-                },
-                _ => ()
-            }
-        }
-        content
-    }
-}
-
-
-pub fn get_string_case_fn(case_sensitive: bool) -> impl Fn(String) -> String
+pub fn get_string_case_fn(case_sensitive: bool) -> impl Fn(String) -> String + Clone
 {
     if case_sensitive {
         |string| string
@@ -397,4 +315,4 @@ pub fn get_string_case_fn(case_sensitive: bool) -> impl Fn(String) -> String
             string.to_lowercase()
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,89 @@
+//! `--merge`: combine duplicate entries (by key or DOI) instead of
+//! aborting as soon as one is found.
+
+use crate::doi;
+use crate::parser::BibEntry;
+
+/// Merges `b` into `a`, taking the union of their fields. When both
+/// entries define the same field with different non-empty text, the
+/// longer value wins and the conflict is reported on stderr.
+fn merge_into(a: &mut BibEntry, b: BibEntry) {
+    let key = a.key.as_deref().unwrap_or("").to_owned();
+    for (name, b_value) in b.fields {
+        match a.fields.iter_mut().find(|(n, _)| *n == name) {
+            None => a.fields.push((name, b_value)),
+            Some((_, a_value)) => {
+                let a_text = a_value.as_text();
+                let a_text = a_text.trim();
+                let b_text = b_value.as_text();
+                let b_text = b_text.trim();
+                if a_text == b_text {
+                    continue;
+                }
+                if a_text.is_empty() {
+                    *a_value = b_value;
+                } else if b_text.is_empty() {
+                    // keep the existing, non-empty value
+                } else if b_text.len() > a_text.len() {
+                    eprintln!(
+                        "Conflicting '{name}' field while merging key '{key}': keeping '{b_text}' over '{a_text}'"
+                    );
+                    *a_value = b_value;
+                } else {
+                    eprintln!(
+                        "Conflicting '{name}' field while merging key '{key}': keeping '{a_text}' over '{b_text}'"
+                    );
+                }
+            },
+        }
+    }
+}
+
+/// Collapses entries that share a key, or (unless `allow_doi_duplicates`)
+/// a DOI, into one entry each, reporting field conflicts on stderr. Keeps
+/// the first-seen key of every group.
+///
+/// Assumes `entries` is already sorted by `case_fn`-folded key, so
+/// same-key duplicates are adjacent.
+pub fn merge_duplicates<F>(entries: Vec<BibEntry>, case_fn: F, allow_doi_duplicates: bool) -> Vec<BibEntry>
+where
+    F: Fn(String) -> String,
+{
+    let mut by_key: Vec<BibEntry> = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let is_duplicate_key = entry.key.as_deref().is_some_and(|key| {
+            !key.is_empty()
+                && by_key.last().and_then(|last| last.key.as_deref()).is_some_and(|last_key| {
+                    case_fn(last_key.to_owned()) == case_fn(key.to_owned())
+                })
+        });
+        if is_duplicate_key {
+            merge_into(by_key.last_mut().unwrap(), entry);
+        } else {
+            by_key.push(entry);
+        }
+    }
+
+    if allow_doi_duplicates {
+        return by_key;
+    }
+
+    let doi_of = |entry: &BibEntry| {
+        entry
+            .field("doi")
+            .map(|value| value.as_text())
+            .and_then(|text| doi::extract(text.trim()).map(str::to_owned))
+    };
+
+    let mut by_doi: Vec<BibEntry> = Vec::with_capacity(by_key.len());
+    for entry in by_key {
+        let doi = doi_of(&entry);
+        let duplicate_idx =
+            doi.as_ref().and_then(|doi| by_doi.iter().position(|existing| doi_of(existing).as_ref() == Some(doi)));
+        match duplicate_idx {
+            Some(idx) => merge_into(&mut by_doi[idx], entry),
+            None => by_doi.push(entry),
+        }
+    }
+    by_doi
+}
@@ -0,0 +1,540 @@
+//! Tokenizer and recursive-descent parser for BibTeX files.
+//!
+//! Entries used to be kept around as an opaque string and re-scraped with
+//! regexes whenever a feature needed to look at a field (duplicate
+//! detection, DOI checking, author sorting, ...). That breaks on `%`
+//! comments, `@` characters inside field values, `=`/`#` concatenation and
+//! quote-delimited fields, and it makes field-level features impossible.
+//!
+//! This module instead turns the raw text into a sequence of [`Token`]s and
+//! then parses those into a structured [`BibEntry`], so every later pass
+//! can work on `fields` directly.
+
+use std::fmt;
+
+/// A single lexical token, together with the line/column it started at
+/// (1-indexed), used to produce useful parse error messages.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub line: usize,
+    pub col: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    At,
+    Ident(String),
+    LBrace,
+    RBrace,
+    Quote,
+    Comma,
+    Equals,
+    Hash,
+    Value(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub message: String,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (line {}, column {})", self.message, self.line, self.col)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// One part of a field value. BibTeX lets you concatenate several parts
+/// with `#`, e.g. `journal = jnl # { Letters}`, so a field value is really
+/// a `Vec` of these rather than a single string.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValuePart {
+    /// `{...}` - preserved so round-tripping reproduces the original.
+    Braced(String),
+    /// `"..."` - preserved so round-tripping reproduces the original.
+    Quoted(String),
+    /// Undelimited text: a number (`2019`) or a reference to an `@string`
+    /// macro (`jnl`).
+    Bare(String),
+}
+
+impl ValuePart {
+    /// The part's text without its delimiter, used for sorting/searching.
+    pub fn inner_text(&self) -> &str {
+        match self {
+            ValuePart::Braced(s) | ValuePart::Quoted(s) | ValuePart::Bare(s) => s,
+        }
+    }
+}
+
+impl fmt::Display for ValuePart {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValuePart::Braced(s) => write!(f, "{{{s}}}"),
+            ValuePart::Quoted(s) => write!(f, "\"{s}\""),
+            ValuePart::Bare(s) => write!(f, "{s}"),
+        }
+    }
+}
+
+/// The (possibly `#`-concatenated) value assigned to a field.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct FieldValue {
+    pub parts: Vec<ValuePart>,
+}
+
+impl FieldValue {
+    pub fn single(part: ValuePart) -> Self {
+        Self { parts: vec![part] }
+    }
+
+    /// The concatenated text of all parts, delimiters stripped - what the
+    /// duplicate/doi/author/sort-by passes should look at.
+    pub fn as_text(&self) -> String {
+        self.parts.iter().map(ValuePart::inner_text).collect()
+    }
+}
+
+impl fmt::Display for FieldValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rendered: Vec<String> = self.parts.iter().map(ToString::to_string).collect();
+        write!(f, "{}", rendered.join(" # "))
+    }
+}
+
+/// What kind of `@...{...}` block an entry is. Only [`EntryKind::Entry`]
+/// is citable; the others have no key and must not be alphabetized among
+/// articles.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EntryKind {
+    /// A regular entry such as `@article`/`@book`/... - holds the type
+    /// name (lowercase is not forced here so it round-trips as written).
+    Entry(String),
+    /// `@string{name = value}` - a macro definition.
+    StringDef,
+    /// `@preamble{...}`.
+    Preamble,
+    /// `@comment{...}`.
+    Comment,
+}
+
+/// A single parsed `@...{...}` block.
+///
+/// For [`EntryKind::StringDef`], [`EntryKind::Preamble`] and
+/// [`EntryKind::Comment`] there is no `field = value` list, just one raw
+/// value; it is stored as the sole entry of `fields` (key `"value"` for
+/// `@string`, empty for the other two) so all four kinds share one type.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BibEntry {
+    pub kind: EntryKind,
+    pub key: Option<String>,
+    pub fields: Vec<(String, FieldValue)>,
+}
+
+impl BibEntry {
+    /// Looks up a field by name (case-insensitive, as field names are
+    /// normalized to lowercase while parsing).
+    pub fn field(&self, name: &str) -> Option<&FieldValue> {
+        self.fields
+            .iter()
+            .find(|(field_name, _)| field_name == name)
+            .map(|(_, value)| value)
+    }
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | ':' | '.' | '/')
+}
+
+/// Turns raw bib-file text into tokens. Braced/quoted/bare value content
+/// is deliberately *not* tokenized further by [`Lexer::next_token`] - the
+/// parser asks for it explicitly once it knows a value is expected, since
+/// that content may contain characters (`@`, `=`, `,`, ...) that are only
+/// special outside of a value.
+struct Lexer<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    line: usize,
+    col: usize,
+}
+
+impl<'a> Lexer<'a> {
+    fn new(input: &'a str) -> Self {
+        Self { chars: input.chars().peekable(), line: 1, col: 1 }
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        if c == '\n' {
+            self.line += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
+        }
+        Some(c)
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().copied()
+    }
+
+    /// Skips whitespace and `%`-to-end-of-line comments. Only meaningful
+    /// outside of a value, where `%` is not part of the grammar.
+    fn skip_whitespace_and_comments(&mut self) {
+        loop {
+            while matches!(self.peek(), Some(c) if c.is_whitespace()) {
+                self.bump();
+            }
+            if self.peek() == Some('%') {
+                while !matches!(self.peek(), Some('\n') | None) {
+                    self.bump();
+                }
+                continue;
+            }
+            break;
+        }
+    }
+
+    /// The next non-whitespace, non-comment character, without consuming
+    /// it.
+    fn peek_significant(&mut self) -> Option<char> {
+        self.skip_whitespace_and_comments();
+        self.peek()
+    }
+
+    /// Reads the next structural token. Must only be called where a bare
+    /// value is *not* expected (use [`Lexer::read_bare_value`] there
+    /// instead), since this always treats a run of identifier characters
+    /// as [`TokenKind::Ident`].
+    fn next_token(&mut self) -> Option<Result<Token, ParseError>> {
+        self.skip_whitespace_and_comments();
+        let (line, col) = (self.line, self.col);
+        let c = self.peek()?;
+        let kind = match c {
+            '@' => { self.bump(); TokenKind::At },
+            '{' => { self.bump(); TokenKind::LBrace },
+            '}' => { self.bump(); TokenKind::RBrace },
+            '"' => { self.bump(); TokenKind::Quote },
+            ',' => { self.bump(); TokenKind::Comma },
+            '=' => { self.bump(); TokenKind::Equals },
+            '#' => { self.bump(); TokenKind::Hash },
+            _ if is_ident_char(c) => {
+                let mut ident = String::new();
+                while matches!(self.peek(), Some(ch) if is_ident_char(ch)) {
+                    ident.push(self.bump().unwrap());
+                }
+                TokenKind::Ident(ident)
+            },
+            other => {
+                return Some(Err(ParseError {
+                    message: format!("Unexpected character '{other}'"),
+                    line,
+                    col,
+                }));
+            }
+        };
+        Some(Ok(Token { kind, line, col }))
+    }
+
+    /// Reads a bare (undelimited) value - a number, a macro name, or a
+    /// key - as a run of everything up to the next `,`, `}`, `#`, `"` or
+    /// whitespace.
+    fn read_bare_value(&mut self) -> Token {
+        self.skip_whitespace_and_comments();
+        let (line, col) = (self.line, self.col);
+        let mut value = String::new();
+        while matches!(self.peek(), Some(c) if !matches!(c, ',' | '}' | '#' | '"') && !c.is_whitespace())
+        {
+            value.push(self.bump().unwrap());
+        }
+        Token { kind: TokenKind::Value(value), line, col }
+    }
+
+    /// Reads raw text up to (and consuming) the matching closing brace,
+    /// assuming the opening `{` was already consumed.
+    fn read_braced_value(&mut self) -> Result<String, ParseError> {
+        let (line, col) = (self.line, self.col);
+        let mut depth = 1u32;
+        let mut text = String::new();
+        loop {
+            match self.bump() {
+                None => {
+                    return Err(ParseError {
+                        message: "Unexpected end of file inside a braced value".to_owned(),
+                        line,
+                        col,
+                    })
+                },
+                Some('{') => {
+                    depth += 1;
+                    text.push('{');
+                },
+                Some('}') => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(text);
+                    }
+                    text.push('}');
+                },
+                Some(c) => text.push(c),
+            }
+        }
+    }
+
+    /// Reads raw text up to (and consuming) the matching closing quote,
+    /// assuming the opening `"` was already consumed. Braces inside the
+    /// quoted value are balanced so a `"` nested inside `{...}` does not
+    /// end the value early.
+    fn read_quoted_value(&mut self) -> Result<String, ParseError> {
+        let (line, col) = (self.line, self.col);
+        let mut depth = 0u32;
+        let mut text = String::new();
+        loop {
+            match self.bump() {
+                None => {
+                    return Err(ParseError {
+                        message: "Unexpected end of file inside a quoted value".to_owned(),
+                        line,
+                        col,
+                    })
+                },
+                Some('{') => {
+                    depth += 1;
+                    text.push('{');
+                },
+                Some('}') => {
+                    // An unbalanced closing brace in a quoted value (e.g.
+                    // `"Foo}bar"`) isn't itself an error here - only `"`
+                    // ends the value - so clamp instead of underflowing.
+                    depth = depth.saturating_sub(1);
+                    text.push('}');
+                },
+                Some('"') if depth == 0 => return Ok(text),
+                Some(c) => text.push(c),
+            }
+        }
+    }
+}
+
+/// Recursive-descent parser over the token stream produced by [`Lexer`].
+pub struct Parser<'a> {
+    lexer: Lexer<'a>,
+}
+
+impl<'a> Parser<'a> {
+    pub fn new(input: &'a str) -> Self {
+        Self { lexer: Lexer::new(input) }
+    }
+
+    pub fn parse(mut self) -> Result<Vec<BibEntry>, ParseError> {
+        let mut entries = Vec::new();
+        loop {
+            match self.lexer.peek_significant() {
+                None => break,
+                Some('@') => {
+                    self.lexer.bump();
+                    entries.push(self.parse_entry()?);
+                },
+                Some(other) => {
+                    return Err(ParseError {
+                        message: format!(
+                            "Expected '@' to start a new entry, found '{other}'"
+                        ),
+                        line: self.lexer.line,
+                        col: self.lexer.col,
+                    })
+                },
+            }
+        }
+        Ok(entries)
+    }
+
+    fn eof_error(&self, context: &str) -> ParseError {
+        ParseError {
+            message: format!("Unexpected end of file, {context}"),
+            line: self.lexer.line,
+            col: self.lexer.col,
+        }
+    }
+
+    fn expect_ident(&mut self) -> Result<String, ParseError> {
+        match self.lexer.next_token() {
+            Some(Ok(Token { kind: TokenKind::Ident(s), .. })) => Ok(s),
+            Some(Ok(tok)) => Err(ParseError {
+                message: format!("Expected an identifier, found {:?}", tok.kind),
+                line: tok.line,
+                col: tok.col,
+            }),
+            Some(Err(e)) => Err(e),
+            None => Err(self.eof_error("expected an identifier")),
+        }
+    }
+
+    fn expect_equals(&mut self) -> Result<(), ParseError> {
+        match self.lexer.next_token() {
+            Some(Ok(Token { kind: TokenKind::Equals, .. })) => Ok(()),
+            Some(Ok(tok)) => Err(ParseError {
+                message: format!("Expected '=', found {:?}", tok.kind),
+                line: tok.line,
+                col: tok.col,
+            }),
+            Some(Err(e)) => Err(e),
+            None => Err(self.eof_error("expected '='")),
+        }
+    }
+
+    fn expect_rbrace(&mut self) -> Result<(), ParseError> {
+        match self.lexer.next_token() {
+            Some(Ok(Token { kind: TokenKind::RBrace, .. })) => Ok(()),
+            Some(Ok(tok)) => Err(ParseError {
+                message: format!("Expected '}}', found {:?}", tok.kind),
+                line: tok.line,
+                col: tok.col,
+            }),
+            Some(Err(e)) => Err(e),
+            None => Err(self.eof_error("expected '}'")),
+        }
+    }
+
+    /// Parses everything after the leading `@` of an `@type{...}` block.
+    fn parse_entry(&mut self) -> Result<BibEntry, ParseError> {
+        let ty = self.expect_ident()?;
+        match self.lexer.next_token() {
+            Some(Ok(Token { kind: TokenKind::LBrace, .. })) => {},
+            Some(Ok(tok)) => {
+                return Err(ParseError {
+                    message: format!("Expected '{{' after entry type, found {:?}", tok.kind),
+                    line: tok.line,
+                    col: tok.col,
+                })
+            },
+            Some(Err(e)) => return Err(e),
+            None => return Err(self.eof_error("expected '{' after entry type")),
+        }
+        match ty.to_lowercase().as_str() {
+            "string" => self.parse_string_def(),
+            "preamble" => self.parse_raw_entry(EntryKind::Preamble),
+            "comment" => self.parse_raw_entry(EntryKind::Comment),
+            _ => self.parse_regular_entry(ty),
+        }
+    }
+
+    /// `@preamble{...}` / `@comment{...}` - a single value, no key.
+    fn parse_raw_entry(&mut self, kind: EntryKind) -> Result<BibEntry, ParseError> {
+        let value = self.parse_value()?;
+        self.expect_rbrace()?;
+        Ok(BibEntry { kind, key: None, fields: vec![(String::new(), value)] })
+    }
+
+    /// `@string{name = value}`.
+    fn parse_string_def(&mut self) -> Result<BibEntry, ParseError> {
+        let name = self.expect_ident()?;
+        self.expect_equals()?;
+        let value = self.parse_value()?;
+        self.expect_rbrace()?;
+        Ok(BibEntry {
+            kind: EntryKind::StringDef,
+            key: Some(name.to_lowercase()),
+            fields: vec![("value".to_owned(), value)],
+        })
+    }
+
+    /// `@article{key, field = value, ...}` and friends.
+    fn parse_regular_entry(&mut self, ty: String) -> Result<BibEntry, ParseError> {
+        let key = self.parse_key();
+        let mut fields = Vec::new();
+        loop {
+            match self.lexer.peek_significant() {
+                None => return Err(self.eof_error("inside an entry body")),
+                Some('}') => {
+                    self.lexer.bump();
+                    break;
+                },
+                Some(',') => {
+                    self.lexer.bump();
+                },
+                Some(_) => {
+                    let name = self.expect_ident()?;
+                    self.expect_equals()?;
+                    let value = self.parse_value()?;
+                    fields.push((name.to_lowercase(), value));
+                },
+            }
+        }
+        Ok(BibEntry { kind: EntryKind::Entry(ty), key, fields })
+    }
+
+    /// The key is read as a bare run up to the first comma/closing brace,
+    /// matching the original `[^,\s]+` heuristic. An empty key means the
+    /// entry had none at all.
+    fn parse_key(&mut self) -> Option<String> {
+        match self.lexer.read_bare_value().kind {
+            TokenKind::Value(s) if !s.trim().is_empty() => Some(s.trim().to_owned()),
+            _ => None,
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<FieldValue, ParseError> {
+        let mut parts = vec![self.parse_value_part()?];
+        while self.lexer.peek_significant() == Some('#') {
+            self.lexer.bump();
+            parts.push(self.parse_value_part()?);
+        }
+        Ok(FieldValue { parts })
+    }
+
+    fn parse_value_part(&mut self) -> Result<ValuePart, ParseError> {
+        match self.lexer.peek_significant() {
+            Some('{') => {
+                self.lexer.bump();
+                Ok(ValuePart::Braced(self.lexer.read_braced_value()?))
+            },
+            Some('"') => {
+                self.lexer.bump();
+                Ok(ValuePart::Quoted(self.lexer.read_quoted_value()?))
+            },
+            Some(_) => match self.lexer.read_bare_value().kind {
+                TokenKind::Value(s) => Ok(ValuePart::Bare(s)),
+                _ => unreachable!(),
+            },
+            None => Err(self.eof_error("while reading a field value")),
+        }
+    }
+}
+
+/// Parses a whole bib file's contents into its entries, in source order.
+pub fn parse(input: &str) -> Result<Vec<BibEntry>, ParseError> {
+    Parser::new(input).parse()
+}
+
+/// Renders a single entry back into BibTeX text.
+pub fn format_entry(entry: &BibEntry) -> String {
+    match &entry.kind {
+        EntryKind::Entry(ty) => {
+            let key = entry.key.as_deref().unwrap_or("");
+            let mut out = format!("@{ty}{{{key}");
+            for (name, value) in &entry.fields {
+                out.push_str(&format!(",\n    {name} = {value}"));
+            }
+            out.push_str("\n}");
+            out
+        },
+        EntryKind::StringDef => {
+            let name = entry.key.as_deref().unwrap_or("");
+            let value = entry.field("value").map(ToString::to_string).unwrap_or_default();
+            format!("@string{{{name} = {value}}}")
+        },
+        EntryKind::Preamble => {
+            let value = entry.fields.first().map(|(_, v)| v.to_string()).unwrap_or_default();
+            format!("@preamble{{{value}}}")
+        },
+        EntryKind::Comment => {
+            let value = entry.fields.first().map(|(_, v)| v.to_string()).unwrap_or_default();
+            format!("@comment{{{value}}}")
+        },
+    }
+}
@@ -1,68 +1,51 @@
-use crate::BibEntry;
-use regex::Regex;
+use crate::parser::BibEntry;
 use lazy_static::lazy_static;
-
+use regex::Regex;
 
 lazy_static! {
-    static ref AUTHOR_POS_REGEX: Regex = {
-        Regex::new(r"(?i)\bauthor\s*=\s*")
-            .unwrap()
-    };
+    static ref AND: Regex = Regex::new(r"(?i)\band\b").unwrap();
+}
 
-    static ref AUTHOR_REGEX: Regex = {
-        Regex::new(r"\w+\s*,?\s*\w*")
-            .unwrap()
-    };
+/// The text of the entry's `author` field, or `""` if it has none.
+fn author_field_text(entry: &BibEntry) -> String {
+    entry.field("author").map(|value| value.as_text()).unwrap_or_default()
+}
 
-    static ref AND: Regex = {
-        Regex::new(r"(?i)\band\b")
-            .unwrap()
+/// The first author listed in the `author` field (authors are separated
+/// by `and`), still containing its braces/quotes - needed so
+/// [`Name::parse`] can tell a brace-protected token (`{von Neumann}`)
+/// apart from a lowercase "von" particle.
+fn raw_first_author(entry: &BibEntry) -> String {
+    let text = author_field_text(entry);
+    let first = match AND.find(&text) {
+        Some(and_match) => &text[..and_match.start()],
+        None => &text,
     };
+    first.trim().to_owned()
 }
 
-
-
-pub fn first_author_from_content(content: &str) -> String
-{
-    match AUTHOR_POS_REGEX.find(content){
-        None => {
-            "".to_owned()
-        },
-        Some(author_pos_match) => {
-            let mut author_field_next = &content[author_pos_match.end()..];
-
-            author_field_next = first_author_field_content(author_field_next);
-
-            if let Some(and_match) =  AND.find(author_field_next) {
-                author_field_next = &author_field_next[..and_match.start()];
-            }
-
-            clean_string(author_field_next)
-
-        }
-    }
+/// The first author listed in the `author` field, with surrounding
+/// whitespace and brace/quote noise removed.
+pub fn first_author_from_entry(entry: &BibEntry) -> String {
+    clean_string(&raw_first_author(entry))
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum Mode{
+enum Mode {
     NotEscaped,
-    Escaped   
+    Escaped,
 }
 
-pub fn clean_string(s: &str) -> String
-{
+pub fn clean_string(s: &str) -> String {
     let mut string = String::with_capacity(s.len());
     let mut mode = Mode::NotEscaped;
-    for c in s.chars()
-    {
+    for c in s.chars() {
         match c {
             char if mode == Mode::Escaped => {
-                // If mode is escaped: next char is always included
                 string.push(char);
                 mode = Mode::NotEscaped;
             },
             '\'' | '\"' | '{' | '}' if mode == Mode::NotEscaped => {
-                // ignoring those if not escaped
                 continue;
             },
             '\\' => {
@@ -72,128 +55,215 @@ pub fn clean_string(s: &str) -> String
             _ => {
                 string.push(c);
                 mode = Mode::NotEscaped;
-            }
+            },
         }
     }
     string
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum BracketOrQuote{
-    None,
-    OpenBracket(u32),
-    SingleQuote,
-    DoubleQuote
+/// One whitespace-separated token of a name, with whether it counts as
+/// "lowercase" for von-part detection. A brace-protected token
+/// (`{von Neumann}`) is never split on its internal whitespace and is
+/// always treated as uppercase, regardless of its contents.
+struct NameToken {
+    text: String,
+    is_lower: bool,
 }
 
+fn is_lower_start(s: &str) -> bool {
+    s.chars().find(|c| c.is_alphabetic()).is_some_and(char::is_lowercase)
+}
 
-pub fn first_author_field_content(mut field: &str) -> &str
-{
-    let mut bracket_or_quote = BracketOrQuote::None;
-    let mut start = 0;
-    let mut iter = field.char_indices();
-    
-    while let Some((index, char)) = iter.next(){
-
-        if char == '\\' {
-            // skip next char
-            let _ = iter.next();
+/// Splits a name part on whitespace, keeping a `{...}`-delimited run as a
+/// single token.
+fn tokenize(part: &str) -> Vec<NameToken> {
+    let mut tokens = Vec::new();
+    let mut chars = part.char_indices().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        if c == '{' {
+            chars.next();
+            let mut depth = 1u32;
+            let mut close = part.len();
+            for (idx, ch) in chars.by_ref() {
+                match ch {
+                    '{' => depth += 1,
+                    '}' => {
+                        depth -= 1;
+                        if depth == 0 {
+                            close = idx;
+                            break;
+                        }
+                    },
+                    _ => {},
+                }
+            }
+            let inner_start = start + 1;
+            let text = part[inner_start..close.max(inner_start)].to_owned();
+            tokens.push(NameToken { text, is_lower: false });
             continue;
         }
+        let mut end = part.len();
+        for (idx, ch) in chars.by_ref() {
+            if ch.is_whitespace() {
+                end = idx;
+                break;
+            }
+        }
+        let text = part[start..end].to_owned();
+        let is_lower = is_lower_start(&text);
+        tokens.push(NameToken { text, is_lower });
+    }
+    tokens
+}
+
+fn join(tokens: &[NameToken]) -> String {
+    tokens.iter().map(|t| t.text.as_str()).collect::<Vec<_>>().join(" ")
+}
+
+/// The maximal run of lowercase tokens within `middle`, as
+/// `middle`-relative `(start, end)` indices, or `None` if there is none.
+fn find_lower_run(middle: &[NameToken]) -> Option<(usize, usize)> {
+    let start = middle.iter().position(|t| t.is_lower)?;
+    let len = middle[start..].iter().take_while(|t| t.is_lower).count();
+    Some((start, start + len))
+}
+
+/// Splits `tokens` (a "von Last" part, no separate "First") into its
+/// `von` and `Last` pieces: `von` is the maximal run of lowercase tokens
+/// among everything but the final token.
+fn split_von_last(tokens: &[NameToken]) -> (String, String) {
+    if tokens.len() <= 1 {
+        return (String::new(), join(tokens));
+    }
+    let last_idx = tokens.len() - 1;
+    match find_lower_run(&tokens[..last_idx]) {
+        None => (String::new(), join(tokens)),
+        Some((start, end)) => {
+            let von = join(&tokens[start..end]);
+            let mut last_tokens = Vec::new();
+            last_tokens.extend(tokens[..start].iter().map(|t| t.text.as_str()));
+            last_tokens.extend(tokens[end..].iter().map(|t| t.text.as_str()));
+            (von, last_tokens.join(" "))
+        },
+    }
+}
+
+/// A parsed BibTeX author name, per the three canonical forms: "First
+/// von Last", "von Last, First" and "von Last, Jr, First".
+#[derive(Debug, Clone, Default)]
+pub struct Name {
+    pub von: String,
+    pub last: String,
+    pub jr: String,
+    pub first: String,
+}
 
-        let mut set_field = || {
-            field = match iter.next(){
-                Some((idx, _)) => {
-                    &field[start..idx]
-                },
-                None => {
-                    &field[start..]
+impl Name {
+    /// Splits `name` on top-level commas (commas inside a `{...}` group
+    /// don't count) and parses whichever of the three canonical forms
+    /// that many commas imply.
+    pub fn parse(name: &str) -> Self {
+        let comma_parts = split_top_level_commas(name);
+        match comma_parts.as_slice() {
+            [first_von_last] => {
+                let tokens = tokenize(first_von_last);
+                if tokens.is_empty() {
+                    return Name::default();
                 }
-            };
-        };
-
-        match &mut bracket_or_quote {
-            BracketOrQuote::None => {
-                start = index;
-                match char {
-                    '{' => {
-                        bracket_or_quote = BracketOrQuote::OpenBracket(1);
-                    },
-                    '\'' => {
-                        bracket_or_quote = BracketOrQuote::SingleQuote;
+                if tokens.len() == 1 {
+                    return Name { last: tokens[0].text.clone(), ..Name::default() };
+                }
+                let last_idx = tokens.len() - 1;
+                let run = find_lower_run(&tokens[1..last_idx])
+                    .map(|(start, end)| (1 + start, 1 + end));
+                match run {
+                    None => Name {
+                        first: join(&tokens[..last_idx]),
+                        last: tokens[last_idx].text.clone(),
+                        ..Name::default()
                     },
-                    '"' => {
-                        bracket_or_quote = BracketOrQuote::DoubleQuote;
+                    Some((start, end)) => Name {
+                        first: join(&tokens[..start]),
+                        von: join(&tokens[start..end]),
+                        last: join(&tokens[end..]),
+                        ..Name::default()
                     },
-                    _ => continue
                 }
             },
-            BracketOrQuote::OpenBracket(num) => {
-                match char {
-                    '{' => *num += 1,
-                    '}' => *num -= 1,
-                    _ => continue
-                }
-                if *num == 0 {
-                    set_field();
-                    break;
-                }
+            [von_last, first] => {
+                let (von, last) = split_von_last(&tokenize(von_last));
+                Name { von, last, first: first.trim().to_owned(), ..Name::default() }
             },
-            BracketOrQuote::SingleQuote => {
-                if char == '\'' {
-                    set_field();
-                    break;
+            // "von Last, Jr, First" per the grammar - but also anything
+            // with more top-level commas than that, which folds every
+            // part past "Jr" into First rather than panicking on one
+            // malformed entry.
+            [von_last, jr, rest @ ..] => {
+                let (von, last) = split_von_last(&tokenize(von_last));
+                Name {
+                    von,
+                    last,
+                    jr: jr.trim().to_owned(),
+                    first: rest.join(", "),
                 }
             },
-            BracketOrQuote::DoubleQuote => {
-                if char == '"' {
-                    set_field();
-                    break;
-                }
-            }
+            // split_top_level_commas always yields at least one part.
+            [] => Name::default(),
         }
     }
 
-    field
+    /// The key used for sorting: `Last von First Jr`, so particles like
+    /// "von" sort with the surname rather than the given name.
+    pub fn sort_key(&self) -> String {
+        [&self.last, &self.von, &self.first, &self.jr]
+            .into_iter()
+            .filter(|part| !part.is_empty())
+            .cloned()
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
 }
 
-pub fn first_author_first_name(content: &str) -> String
-{
-    let first_author_field_content = first_author_from_content(content);
-    let first_author_field_content = first_author_field_content.trim(); // also trim potential spaces
-    // author field might contain one or more "," - like author = {Feld, Yannick AND Hartmann, Alexander K.}
-    // We already removed all other authors, so this should contain at most one ","
-
-    let mut iter = first_author_field_content.split(',');
-    let first_entry = iter.next().unwrap();
-    let second_entry = iter.next();
-    assert_eq!(
-        iter.next(), 
-        None,
-        "To many ',' in first author of a bib entry: {content}\nauthor_field: {}",
-        first_author_field_content
-    );
-    match second_entry{
-        None => {
-            first_entry.trim().to_owned()
-        },
-        Some(first_name) => {
-            format!("{} {}", first_name.trim(), first_entry.trim())
+/// Splits on `,` that are not nested inside a `{...}` group.
+fn split_top_level_commas(s: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0u32;
+    let mut start = 0;
+    for (idx, c) in s.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth = depth.saturating_sub(1),
+            ',' if depth == 0 => {
+                parts.push(s[start..idx].trim());
+                start = idx + 1;
+            },
+            _ => {},
         }
     }
+    parts.push(s[start..].trim());
+    parts
+}
 
+/// The first author's sort key, honoring von/Jr/comma-order per the
+/// standard BibTeX name grammars.
+pub fn first_author_sort_key(entry: &BibEntry) -> String {
+    Name::parse(&raw_first_author(entry)).sort_key()
 }
 
 pub fn sort_by_first_author_field<F>(to_sort: &mut [BibEntry], case_fn: F)
-where F: Fn(String) -> String
+where
+    F: Fn(String) -> String,
 {
-    to_sort
-        .sort_by_cached_key(|entry| case_fn(first_author_from_content(&entry.content)));
+    to_sort.sort_by_cached_key(|entry| case_fn(first_author_from_entry(entry)));
 }
 
 pub fn sort_by_first_author_first_name<F>(to_sort: &mut [BibEntry], case_fn: F)
-where F: Fn(String) -> String
+where
+    F: Fn(String) -> String,
 {
-    to_sort
-        .sort_by_cached_key(|entry| case_fn(first_author_first_name(&entry.content)));
-}
\ No newline at end of file
+    to_sort.sort_by_cached_key(|entry| case_fn(first_author_sort_key(entry)));
+}